@@ -0,0 +1,65 @@
+use crate::jit::assemble;
+use crate::parser::{Opcode, Program};
+use std::collections::HashSet;
+
+/// Render `program` as a numbered assembly-style listing: `[`/`]` pairs print
+/// as named labels (`L3:`) resolved from their `closing_pc`/`opening_pc`
+/// instead of the lossy single-char `Display` impl, and the optimizer's
+/// fused opcodes (`LoopSetToZero`, `LoopMovePtr`, `LoopMoveData`) print with
+/// their operands. When `show_bytes` is set, the native bytes the JIT would
+/// emit for each opcode are printed alongside it in hex.
+pub(crate) fn disassemble(program: &Program, show_bytes: bool) {
+    let assembled = show_bytes.then(|| assemble(program));
+    let targets = jump_targets(program);
+
+    for (pc, opcode) in program.instructions.iter().enumerate() {
+        if targets.contains(&pc) {
+            println!("L{}:", pc);
+        }
+
+        print!("  {:>4}: {}", pc, mnemonic(opcode));
+
+        if let Some((code, ranges)) = &assembled {
+            let (start, end) = ranges[pc];
+            let hex = code[start..end]
+                .iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<Vec<_>>()
+                .join(" ");
+            print!("    ; {}", hex);
+        }
+
+        println!();
+    }
+}
+
+fn mnemonic(opcode: &Opcode) -> String {
+    match opcode {
+        Opcode::IncPtr(n) => format!(">  ({})", n),
+        Opcode::DecPtr(n) => format!("<  ({})", n),
+        Opcode::IncData(n) => format!("+  ({})", n),
+        Opcode::DecData(n) => format!("-  ({})", n),
+        Opcode::ReadStdin => ",".to_string(),
+        Opcode::WriteStdout => ".".to_string(),
+        Opcode::LoopSetToZero => "LOOP_SET_TO_ZERO".to_string(),
+        Opcode::LoopMovePtr(stride, true) => format!("LOOP_MOVE_PTR  (+{})", stride),
+        Opcode::LoopMovePtr(stride, false) => format!("LOOP_MOVE_PTR  (-{})", stride),
+        Opcode::LoopMoveData(stride, true) => format!("LOOP_MOVE_DATA  (+{})", stride),
+        Opcode::LoopMoveData(stride, false) => format!("LOOP_MOVE_DATA  (-{})", stride),
+        Opcode::JumpIfDataZero(closing_pc) => format!("[  -> L{}", closing_pc),
+        Opcode::JumpIfDataNotZero(opening_pc) => format!("]  -> L{}", opening_pc),
+    }
+}
+
+/// Every pc referenced as a jump target, i.e. every pc that needs a label.
+fn jump_targets(program: &Program) -> HashSet<usize> {
+    program
+        .instructions
+        .iter()
+        .filter_map(|opcode| match opcode {
+            Opcode::JumpIfDataZero(closing_pc) => Some(*closing_pc),
+            Opcode::JumpIfDataNotZero(opening_pc) => Some(*opening_pc),
+            _ => None,
+        })
+        .collect()
+}
@@ -2,10 +2,43 @@ use crate::Program;
 use crate::parser::Opcode;
 use std::io::Read;
 
-pub(crate) fn interpret(program: &Program) {
-    let mut memory = [0_u8; 30_000];
+/// Interpreter tunables. `tape_size` bounds how many memory cells exist;
+/// `wrap_pointer` selects between the classic wrapping-tape Brainfuck
+/// dialect (pointer moves modulo `tape_size`) and trapping on out-of-range
+/// moves; `max_steps` bounds how much work the program may do before it
+/// traps, so an adversarial program can't hang the caller forever.
+pub(crate) struct Config {
+    pub(crate) tape_size: usize,
+    pub(crate) wrap_pointer: bool,
+    pub(crate) max_steps: Option<u64>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            tape_size: 30_000,
+            wrap_pointer: false,
+            max_steps: None,
+        }
+    }
+}
+
+/// A condition that stops interpretation before the program finishes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Trap {
+    /// The data pointer moved below cell 0.
+    PointerUnderflow { pc: usize },
+    /// The data pointer moved past `tape_size - 1`, landing on `addr`.
+    PointerOverflow { pc: usize, addr: usize },
+    /// `max_steps` was reached before the program finished.
+    StepLimitExceeded { pc: usize },
+}
+
+pub(crate) fn interpret(program: &Program, config: &Config) -> Result<(), Trap> {
+    let mut memory = vec![0_u8; config.tape_size];
     let mut pc = 0;
     let mut data_ptr = 0;
+    let mut steps = 0_u64;
 
     #[cfg(feature = "tracing")]
     let mut insn_count = std::collections::HashMap::new();
@@ -19,11 +52,18 @@ pub(crate) fn interpret(program: &Program) {
             .and_modify(|v| *v += 1)
             .or_insert(1);
 
+        // every dispatched opcode costs at least one tick of the budget;
+        // fused loop opcodes charge additional ticks below for the
+        // underlying Brainfuck work they stand in for
+        charge(&mut steps, 1, config.max_steps, pc)?;
+
         match insn {
-            // advance the data ptr to the right by 1
-            Opcode::IncPtr(count) => data_ptr += *count as usize,
-            // advance the data ptr to the left by 1
-            Opcode::DecPtr(count) => data_ptr -= *count as usize,
+            // advance the data ptr to the right by `count`
+            Opcode::IncPtr(count) => data_ptr = move_ptr(data_ptr, *count as isize, config, pc)?,
+            // advance the data ptr to the left by `count`
+            Opcode::DecPtr(count) => {
+                data_ptr = move_ptr(data_ptr, -(*count as isize), config, pc)?
+            }
             // increment the memory slot at the data ptr
             Opcode::IncData(count) => memory[data_ptr] = memory[data_ptr].wrapping_add(*count),
             // decrement the memory slot at the data ptr
@@ -34,26 +74,28 @@ pub(crate) fn interpret(program: &Program) {
             Opcode::ReadStdin => memory[data_ptr] = read_byte(),
             // set the current memory value to 0
             Opcode::LoopSetToZero => memory[data_ptr] = 0,
-            // advance the data ptr by +/- stride
+            // advance the data ptr by +/- stride, until it lands on a cell containing 0
             Opcode::LoopMovePtr(stride, positive) => {
                 while memory[data_ptr] != 0 {
-                    if *positive {
-                        data_ptr += *stride as usize
-                    } else {
-                        data_ptr -= *stride as usize
-                    }
+                    // each pass stands in for `stride` raw pointer-move ticks
+                    charge(&mut steps, *stride as u64, config.max_steps, pc)?;
+                    let delta = signed_delta(*stride, *positive);
+                    data_ptr = move_ptr(data_ptr, delta, config, pc)?;
                 }
             }
-            // add the current of src data to the +/- stride memory slot
+            // add the current value of src data to the +/- stride memory slot
             Opcode::LoopMoveData(stride, positive) => {
                 if memory[data_ptr] != 0 {
-                    let new_addr = if *positive {
-                        data_ptr + *stride as usize
-                    } else {
-                        data_ptr - *stride as usize
-                    };
+                    // stands in for one pass per unit of the source cell, each pass
+                    // being `stride` pointer moves out, `stride` back, plus the
+                    // DecData/IncData pair
+                    let passes = memory[data_ptr] as u64;
+                    charge(&mut steps, (2 * *stride as u64 + 2) * passes, config.max_steps, pc)?;
+
+                    let delta = signed_delta(*stride, *positive);
+                    let new_addr = move_ptr(data_ptr, delta, config, pc)?;
 
-                    memory[new_addr] += memory[data_ptr];
+                    memory[new_addr] = memory[new_addr].wrapping_add(memory[data_ptr]);
                     memory[data_ptr] = 0;
                 }
             }
@@ -84,6 +126,54 @@ pub(crate) fn interpret(program: &Program) {
         }
         println!("Total: {}", comma_format(insn_count.values().sum::<u64>()));
     }
+
+    Ok(())
+}
+
+/// Charge `cost` ticks against the step budget, trapping instead of
+/// overrunning `max_steps` (when set).
+fn charge(steps: &mut u64, cost: u64, max_steps: Option<u64>, pc: usize) -> Result<(), Trap> {
+    let next = steps.checked_add(cost).unwrap_or(u64::MAX);
+    if max_steps.is_some_and(|max| next > max) {
+        return Err(Trap::StepLimitExceeded { pc });
+    }
+    *steps = next;
+    Ok(())
+}
+
+fn signed_delta(stride: u8, positive: bool) -> isize {
+    if positive {
+        stride as isize
+    } else {
+        -(stride as isize)
+    }
+}
+
+/// Apply `delta` to `data_ptr`, bounds-checking against `config.tape_size`.
+/// Out-of-range moves wrap modulo `tape_size` when `config.wrap_pointer` is
+/// set, otherwise they trap.
+fn move_ptr(data_ptr: usize, delta: isize, config: &Config, pc: usize) -> Result<usize, Trap> {
+    let new_addr = data_ptr as isize + delta;
+    let tape_size = config.tape_size as isize;
+
+    if new_addr < 0 {
+        if config.wrap_pointer {
+            Ok(new_addr.rem_euclid(tape_size) as usize)
+        } else {
+            Err(Trap::PointerUnderflow { pc })
+        }
+    } else if new_addr >= tape_size {
+        if config.wrap_pointer {
+            Ok((new_addr % tape_size) as usize)
+        } else {
+            Err(Trap::PointerOverflow {
+                pc,
+                addr: new_addr as usize,
+            })
+        }
+    } else {
+        Ok(new_addr as usize)
+    }
 }
 
 fn read_byte() -> u8 {
@@ -1,23 +1,25 @@
-use std::{default, ptr};
+use crate::parser::{Opcode, Program};
+use std::mem::transmute;
+use std::ptr;
 
-/// JIT Notes
-///
-/// there are two phases
-/// 1. Generate the instruction stream
-/// 2. Put the instruction stream in memory and then execute it
-///
-/// Generate the isntruction stream
-/// - multiple ways to do this, but this is essentially compilation
-/// - take some representation of something (usually at a higher abstraction level)
-///   convert it to another representation (usually at a lower abstraction level)
-///
-/// Execute the instruction stream
-/// - first we need to allocate memory (page-aligned) to hold the instruction stream
-///     - initially set to RW permissions (os dependent)
-/// - next we copy the instruction stream to the allocated memory
-/// - we then change the permissions of allocated range to READ_EXEC (RX)
-/// - cast the pointer to a function pointer
-/// - perform a function call
+// JIT Notes
+//
+// there are two phases
+// 1. Generate the instruction stream
+// 2. Put the instruction stream in memory and then execute it
+//
+// Generate the isntruction stream
+// - multiple ways to do this, but this is essentially compilation
+// - take some representation of something (usually at a higher abstraction level)
+//   convert it to another representation (usually at a lower abstraction level)
+//
+// Execute the instruction stream
+// - first we need to allocate memory (page-aligned) to hold the instruction stream
+//     - initially set to RW permissions (os dependent)
+// - next we copy the instruction stream to the allocated memory
+// - we then change the permissions of allocated range to READ_EXEC (RX)
+// - cast the pointer to a function pointer
+// - perform a function call
 
 struct CodeBuilder {
     bytes: Vec<u8>,
@@ -34,11 +36,437 @@ impl CodeBuilder {
         self
     }
 
-    /// Append u32 (as little endian bytes) to the code stream
-    fn emit_u32(&mut self, val: u32) -> &mut Self {
+    /// Append a single byte to the code stream
+    fn emit_u8(&mut self, val: u8) -> &mut Self {
+        self.bytes.push(val);
+        self
+    }
+
+    /// Append i32 (as little endian bytes) to the code stream
+    fn emit_i32(&mut self, val: i32) -> &mut Self {
         self.bytes.extend_from_slice(val.to_le_bytes().as_slice());
         self
     }
+
+    /// Overwrite the 4 bytes at `offset` with `val`.
+    /// Used to backpatch relative jump displacements once the jump target
+    /// is known, e.g. resolving a `[`/`]` pair after both have been emitted.
+    fn patch_i32(&mut self, offset: usize, val: i32) {
+        self.bytes[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+    }
+}
+
+/// x86-64 registers the JIT backend addresses by name. Only the low 8
+/// registers are needed, so every encoding below can assume 3-bit register
+/// fields and skip the REX.B/.X/.R extension bits entirely.
+///
+/// Named exhaustively (not just the ones `jit_compile` currently uses) so
+/// the assembler layer reads as a general-purpose encoder rather than one
+/// hardcoded to this backend's register choices.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[allow(dead_code)]
+pub(crate) enum Reg {
+    Rax,
+    Rcx,
+    Rdx,
+    Rbx,
+    Rsp,
+    Rbp,
+    Rsi,
+    Rdi,
+}
+
+impl Reg {
+    fn code(self) -> u8 {
+        match self {
+            Reg::Rax => 0,
+            Reg::Rcx => 1,
+            Reg::Rdx => 2,
+            Reg::Rbx => 3,
+            Reg::Rsp => 4,
+            Reg::Rbp => 5,
+            Reg::Rsi => 6,
+            Reg::Rdi => 7,
+        }
+    }
+}
+
+/// Pack a ModR/M byte. Only `[base]`, `[base+disp8]` and `[base+disp32]`
+/// addressing is ever needed here, so there's no SIB support.
+fn modrm(mod_bits: u8, reg: u8, rm: u8) -> u8 {
+    (mod_bits << 6) | ((reg & 0x7) << 3) | (rm & 0x7)
+}
+
+/// A jump target that may not be known yet. `je`/`jne`/`jmp` record a fixup
+/// against it; `finalize` fails if it was never `bind`-ed.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Label(usize);
+
+/// A pending relative-jump displacement, recorded at emit time and resolved
+/// once its label is bound.
+struct Fixup {
+    /// offset of the rel32 operand to overwrite
+    operand: usize,
+    /// offset right after the operand, i.e. what the displacement is relative to
+    instr_end: usize,
+    label: Label,
+}
+
+/// Typed assembler over [`CodeBuilder`]: emits x86-64 instructions from
+/// mnemonics (`mov_reg_reg`, `je`, ...) instead of hand-encoded opcode bytes,
+/// and resolves symbolic [`Label`]s into relative displacements at
+/// `finalize()`. This is what the Brainfuck backend in [`jit_compile`] is
+/// written against.
+pub(crate) struct Assembler {
+    builder: CodeBuilder,
+    label_positions: Vec<Option<usize>>,
+    fixups: Vec<Fixup>,
+}
+
+impl Assembler {
+    pub(crate) fn new() -> Self {
+        Self {
+            builder: CodeBuilder::new(),
+            label_positions: vec![],
+            fixups: vec![],
+        }
+    }
+
+    /// Allocate a new, as-yet-unbound label.
+    pub(crate) fn label(&mut self) -> Label {
+        self.label_positions.push(None);
+        Label(self.label_positions.len() - 1)
+    }
+
+    /// Bind `label` to the current write position.
+    pub(crate) fn bind(&mut self, label: Label) {
+        self.label_positions[label.0] = Some(self.builder.bytes.len());
+    }
+
+    pub(crate) fn push_reg(&mut self, reg: Reg) {
+        self.builder.emit_u8(0x50 + reg.code());
+    }
+
+    pub(crate) fn pop_reg(&mut self, reg: Reg) {
+        self.builder.emit_u8(0x58 + reg.code());
+    }
+
+    /// `mov dst, src` (64-bit)
+    pub(crate) fn mov_reg_reg(&mut self, dst: Reg, src: Reg) {
+        self.builder
+            .emit_bytes(&[0x48, 0x89, modrm(0b11, src.code(), dst.code())]);
+    }
+
+    /// `movabs reg, imm64`
+    pub(crate) fn mov_reg_imm64(&mut self, reg: Reg, val: u64) {
+        self.builder.emit_bytes(&[0x48, 0xb8 + reg.code()]);
+        self.builder.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    /// `add reg, imm8` (64-bit, sign-extended)
+    pub(crate) fn add_reg_imm8(&mut self, reg: Reg, imm8: u8) {
+        self.builder
+            .emit_bytes(&[0x48, 0x83, modrm(0b11, 0, reg.code())])
+            .emit_u8(imm8);
+    }
+
+    /// `sub reg, imm8` (64-bit, sign-extended)
+    pub(crate) fn sub_reg_imm8(&mut self, reg: Reg, imm8: u8) {
+        self.builder
+            .emit_bytes(&[0x48, 0x83, modrm(0b11, 5, reg.code())])
+            .emit_u8(imm8);
+    }
+
+    /// `add reg, imm32` (64-bit, sign-extended from imm32)
+    pub(crate) fn add_reg_imm32(&mut self, reg: Reg, imm32: i32) {
+        self.builder
+            .emit_bytes(&[0x48, 0x81, modrm(0b11, 0, reg.code())]);
+        self.builder.emit_i32(imm32);
+    }
+
+    /// `sub reg, imm32` (64-bit, sign-extended from imm32)
+    pub(crate) fn sub_reg_imm32(&mut self, reg: Reg, imm32: i32) {
+        self.builder
+            .emit_bytes(&[0x48, 0x81, modrm(0b11, 5, reg.code())]);
+        self.builder.emit_i32(imm32);
+    }
+
+    /// `add reg, n` where `n` is an unsigned byte count (not a signed delta):
+    /// picks the imm8 encoding when `n` fits, otherwise falls back to imm32
+    /// so counts above 127 aren't sign-extended into a negative operand.
+    pub(crate) fn add_reg_count(&mut self, reg: Reg, n: u8) {
+        if n <= i8::MAX as u8 {
+            self.add_reg_imm8(reg, n);
+        } else {
+            self.add_reg_imm32(reg, n as i32);
+        }
+    }
+
+    /// `sub reg, n`, see [`Assembler::add_reg_count`].
+    pub(crate) fn sub_reg_count(&mut self, reg: Reg, n: u8) {
+        if n <= i8::MAX as u8 {
+            self.sub_reg_imm8(reg, n);
+        } else {
+            self.sub_reg_imm32(reg, n as i32);
+        }
+    }
+
+    /// `add byte [base], imm8`
+    pub(crate) fn add_mem_imm8(&mut self, base: Reg, imm8: u8) {
+        self.builder
+            .emit_bytes(&[0x80, modrm(0b00, 0, base.code())])
+            .emit_u8(imm8);
+    }
+
+    /// `sub byte [base], imm8`
+    pub(crate) fn sub_mem_imm8(&mut self, base: Reg, imm8: u8) {
+        self.builder
+            .emit_bytes(&[0x80, modrm(0b00, 5, base.code())])
+            .emit_u8(imm8);
+    }
+
+    /// `cmp byte [base], imm8`
+    pub(crate) fn cmp_mem_imm8(&mut self, base: Reg, imm8: u8) {
+        self.builder
+            .emit_bytes(&[0x80, modrm(0b00, 7, base.code())])
+            .emit_u8(imm8);
+    }
+
+    /// `mov byte [base], imm8`
+    pub(crate) fn mov_mem_imm8(&mut self, base: Reg, imm8: u8) {
+        self.builder
+            .emit_bytes(&[0xc6, modrm(0b00, 0, base.code())])
+            .emit_u8(imm8);
+    }
+
+    /// `mov byte [base], reg` (low 8 bits of `reg`; with no REX prefix,
+    /// `Rsp`/`Rbp`/`Rsi`/`Rdi` encode `ah`/`ch`/`dh`/`bh` instead)
+    pub(crate) fn mov_mem_reg8(&mut self, base: Reg, reg: Reg) {
+        self.builder
+            .emit_bytes(&[0x88, modrm(0b00, reg.code(), base.code())]);
+    }
+
+    /// `mov reg, byte [base]` (low 8 bits of `reg`, no zero-extension; see
+    /// [`Assembler::mov_mem_reg8`] for `Rsp`..`Rdi`)
+    pub(crate) fn mov_reg8_mem(&mut self, reg: Reg, base: Reg) {
+        self.builder
+            .emit_bytes(&[0x8a, modrm(0b00, reg.code(), base.code())]);
+    }
+
+    /// `movzx reg, byte [base]` (32-bit destination)
+    pub(crate) fn movzx_reg_mem8(&mut self, reg: Reg, base: Reg) {
+        self.builder
+            .emit_bytes(&[0x0f, 0xb6, modrm(0b00, reg.code(), base.code())]);
+    }
+
+    /// `add byte [base+disp8], reg` (low 8 bits of `reg`; see
+    /// [`Assembler::mov_mem_reg8`] for `Rsp`..`Rdi`)
+    pub(crate) fn add_mem_disp8_reg8(&mut self, base: Reg, disp8: i8, reg: Reg) {
+        self.builder
+            .emit_bytes(&[0x00, modrm(0b01, reg.code(), base.code())])
+            .emit_u8(disp8 as u8);
+    }
+
+    /// `add byte [base+disp32], reg` (low 8 bits of `reg`; see
+    /// [`Assembler::mov_mem_reg8`] for `Rsp`..`Rdi`)
+    pub(crate) fn add_mem_disp32_reg8(&mut self, base: Reg, disp32: i32, reg: Reg) {
+        self.builder
+            .emit_bytes(&[0x00, modrm(0b10, reg.code(), base.code())]);
+        self.builder.emit_i32(disp32);
+    }
+
+    pub(crate) fn call_reg(&mut self, reg: Reg) {
+        self.builder.emit_bytes(&[0xff, modrm(0b11, 2, reg.code())]);
+    }
+
+    pub(crate) fn ret(&mut self) {
+        self.builder.emit_u8(0xc3);
+    }
+
+    fn jcc(&mut self, opcode: u8, label: Label) {
+        self.builder.emit_bytes(&[0x0f, opcode]);
+        let operand = self.builder.bytes.len();
+        self.builder.emit_i32(0);
+        let instr_end = self.builder.bytes.len();
+        self.fixups.push(Fixup {
+            operand,
+            instr_end,
+            label,
+        });
+    }
+
+    pub(crate) fn je(&mut self, label: Label) {
+        self.jcc(0x84, label);
+    }
+
+    pub(crate) fn jne(&mut self, label: Label) {
+        self.jcc(0x85, label);
+    }
+
+    pub(crate) fn jmp(&mut self, label: Label) {
+        self.builder.emit_u8(0xe9);
+        let operand = self.builder.bytes.len();
+        self.builder.emit_i32(0);
+        let instr_end = self.builder.bytes.len();
+        self.fixups.push(Fixup {
+            operand,
+            instr_end,
+            label,
+        });
+    }
+
+    /// Resolve every label reference recorded by `je`/`jne`/`jmp` and return
+    /// the finished instruction stream.
+    pub(crate) fn finalize(mut self) -> Vec<u8> {
+        for fixup in &self.fixups {
+            let target =
+                self.label_positions[fixup.label.0].expect("jump target label never bound");
+            let rel = target as i64 - fixup.instr_end as i64;
+            self.builder.patch_i32(fixup.operand, rel as i32);
+        }
+        self.builder.bytes
+    }
+}
+
+/// Assemble `program` to native x86-64 code, returning the finished bytes
+/// alongside, for each `program.instructions[i]`, the `[start, end)` byte
+/// range it was encoded into. The ranges are what `--disasm --bytes` uses to
+/// show the machine code next to its source opcode.
+pub(crate) fn assemble(program: &Program) -> (Vec<u8>, Vec<(usize, usize)>) {
+    let mut asm = Assembler::new();
+
+    // prologue: preserve rbx (callee-saved) and pin the tape pointer there
+    asm.push_reg(Reg::Rbx);
+    asm.mov_reg_reg(Reg::Rbx, Reg::Rdi);
+
+    // (loop-start label, loop-end label) for each currently open `[`
+    let mut open_loops: Vec<(Label, Label)> = vec![];
+    let mut ranges = Vec::with_capacity(program.instructions.len());
+
+    for opcode in &program.instructions {
+        let start = asm.builder.bytes.len();
+
+        match opcode {
+            Opcode::IncPtr(n) => asm.add_reg_count(Reg::Rbx, *n),
+            Opcode::DecPtr(n) => asm.sub_reg_count(Reg::Rbx, *n),
+            Opcode::IncData(n) => asm.add_mem_imm8(Reg::Rbx, *n),
+            Opcode::DecData(n) => asm.sub_mem_imm8(Reg::Rbx, *n),
+            Opcode::LoopSetToZero => asm.mov_mem_imm8(Reg::Rbx, 0),
+            Opcode::LoopMovePtr(stride, positive) => {
+                emit_loop_move_ptr(&mut asm, *stride, *positive)
+            }
+            Opcode::LoopMoveData(stride, positive) => {
+                emit_loop_move_data(&mut asm, *stride, *positive)
+            }
+            Opcode::WriteStdout => emit_putchar(&mut asm),
+            Opcode::ReadStdin => emit_getchar(&mut asm),
+            Opcode::JumpIfDataZero(_) => {
+                let start = asm.label();
+                let end = asm.label();
+                asm.bind(start);
+                asm.cmp_mem_imm8(Reg::Rbx, 0);
+                asm.je(end);
+                open_loops.push((start, end));
+            }
+            Opcode::JumpIfDataNotZero(_) => {
+                let (start, end) = open_loops.pop().expect("unmatched ']' reached codegen");
+                asm.cmp_mem_imm8(Reg::Rbx, 0);
+                asm.jne(start);
+                asm.bind(end);
+            }
+        }
+
+        ranges.push((start, asm.builder.bytes.len()));
+    }
+
+    // epilogue: restore rbx and return
+    asm.pop_reg(Reg::Rbx);
+    asm.ret();
+
+    (asm.finalize(), ranges)
+}
+
+/// Compile `program` to native x86-64 code and return it as a callable
+/// function. The returned function takes a pointer to the Brainfuck tape
+/// and pins it in `rbx` for the lifetime of the call.
+///
+/// ABI: `extern "C" fn(*mut u8)` - the tape pointer arrives in `rdi` and is
+/// moved into the callee-saved `rbx`, so it survives the `call`s emitted for
+/// `,`/`.`.
+pub(crate) fn jit_compile(program: &Program) -> extern "C" fn(*mut u8) {
+    let (code, _) = assemble(program);
+    let p = allocate_code(&code);
+    unsafe { transmute(p) }
+}
+
+/// `while memory[data_ptr] != 0 { data_ptr +/-= stride }`
+fn emit_loop_move_ptr(asm: &mut Assembler, stride: u8, positive: bool) {
+    let start = asm.label();
+    let end = asm.label();
+
+    asm.bind(start);
+    asm.cmp_mem_imm8(Reg::Rbx, 0);
+    asm.je(end);
+
+    if positive {
+        asm.add_reg_count(Reg::Rbx, stride);
+    } else {
+        asm.sub_reg_count(Reg::Rbx, stride);
+    }
+
+    asm.jmp(start);
+    asm.bind(end);
+}
+
+/// `if memory[data_ptr] != 0 { memory[data_ptr +/- stride] += memory[data_ptr]; memory[data_ptr] = 0 }`
+fn emit_loop_move_data(asm: &mut Assembler, stride: u8, positive: bool) {
+    let end = asm.label();
+
+    asm.cmp_mem_imm8(Reg::Rbx, 0);
+    asm.je(end);
+
+    asm.mov_reg8_mem(Reg::Rax, Reg::Rbx);
+    let disp = if positive {
+        stride as i32
+    } else {
+        -(stride as i32)
+    };
+    match i8::try_from(disp) {
+        Ok(disp8) => asm.add_mem_disp8_reg8(Reg::Rbx, disp8, Reg::Rax),
+        Err(_) => asm.add_mem_disp32_reg8(Reg::Rbx, disp, Reg::Rax),
+    }
+    asm.mov_mem_imm8(Reg::Rbx, 0);
+
+    asm.bind(end);
+}
+
+/// Forward `memory[data_ptr]` to `putchar`, spilling `rbx` across the call.
+///
+/// The System V ABI requires `rsp` to be 16-byte aligned at `call`. Our
+/// prologue's `push rbx` already leaves `rsp ≡ 0 (mod 16)`, so the extra
+/// `push rbx` here to save it across the call knocks it to `8 (mod 16)`;
+/// `sub rsp, 8` rebalances it before the call and `add rsp, 8` undoes it
+/// after.
+fn emit_putchar(asm: &mut Assembler) {
+    asm.movzx_reg_mem8(Reg::Rdi, Reg::Rbx);
+    asm.push_reg(Reg::Rbx);
+    asm.sub_reg_imm8(Reg::Rsp, 8);
+    asm.mov_reg_imm64(Reg::Rax, libc::putchar as *const () as u64);
+    asm.call_reg(Reg::Rax);
+    asm.add_reg_imm8(Reg::Rsp, 8);
+    asm.pop_reg(Reg::Rbx);
+}
+
+/// Call `getchar` and store the result at `memory[data_ptr]`, spilling `rbx`
+/// across the call (see [`emit_putchar`] for the stack-alignment rationale).
+fn emit_getchar(asm: &mut Assembler) {
+    asm.push_reg(Reg::Rbx);
+    asm.sub_reg_imm8(Reg::Rsp, 8);
+    asm.mov_reg_imm64(Reg::Rax, libc::getchar as *const () as u64);
+    asm.call_reg(Reg::Rax);
+    asm.add_reg_imm8(Reg::Rsp, 8);
+    asm.pop_reg(Reg::Rbx);
+    asm.mov_mem_reg8(Reg::Rbx, Reg::Rax);
 }
 
 /// Stores code bytes in executable memory
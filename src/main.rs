@@ -1,37 +1,70 @@
-use crate::{interpreter::interpret, parser::Program};
+use crate::{
+    disasm::disassemble,
+    interpreter::{Config, Trap, interpret},
+    jit::jit_compile,
+    parser::Program,
+};
 use std::fs;
 
+mod disasm;
 mod interpreter;
 mod jit;
 mod parser;
 
 fn main() {
     let args = std::env::args().collect::<Vec<String>>();
-    let source = fs::read_to_string(&args[1]).unwrap();
 
-    let program = Program::from_source(source);
-    interpret(&program);
-}
-
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn loop_optimization() {
-        let program = Program::from_source(String::from("[-]"));
-        assert_eq!(program.instructions.len(), 1);
-        assert_eq!(program.instructions[0], Opcode::LoopSetToZero);
+    let max_steps = args
+        .iter()
+        .position(|arg| arg == "--max-steps")
+        .and_then(|i| args.get(i + 1))
+        .map(|n| n.parse::<u64>().expect("--max-steps expects an integer"));
 
-        let program = Program::from_source(String::from("[>>]"));
-        assert_eq!(program.instructions.len(), 1);
-        assert_eq!(program.instructions[0], Opcode::LoopMovePtr(2, true));
+    // the source path is the first positional argument: skip `--flag`s and,
+    // for `--max-steps`, the value that follows it, so flags can precede the
+    // path in any order (e.g. `bf-jit --jit prog.bf`).
+    let path = args
+        .iter()
+        .enumerate()
+        .skip(1)
+        .find(|&(i, arg)| {
+            !arg.starts_with("--") && args.get(i - 1).map(String::as_str) != Some("--max-steps")
+        })
+        .map(|(_, arg)| arg)
+        .expect("usage: bf-jit [--jit | --disasm [--bytes]] [--max-steps N] <path>");
+    let source = fs::read_to_string(path).unwrap();
 
-        let program = Program::from_source(String::from("[->>>+<<<]"));
-        assert_eq!(program.instructions.len(), 1);
-        assert_eq!(program.instructions[0], Opcode::LoopMoveData(3, true));
+    let program = match Program::from_source(source) {
+        Ok(program) => program,
+        Err(err) => {
+            eprintln!("{}", err);
+            std::process::exit(1);
+        }
+    };
 
-        let program = Program::from_source(String::from(">>>[-<<<<<<+>>>>>>]"));
-        assert_eq!(program.instructions.len(), 2);
-        assert_eq!(program.instructions[0], Opcode::IncPtr(3));
-        assert_eq!(program.instructions[1], Opcode::LoopMoveData(6, false));
+    if args.iter().any(|arg| arg == "--disasm") {
+        let show_bytes = args.iter().any(|arg| arg == "--bytes");
+        disassemble(&program, show_bytes);
+    } else if args.iter().any(|arg| arg == "--jit") {
+        let mut tape = [0_u8; 30_000];
+        let compiled = jit_compile(&program);
+        compiled(tape.as_mut_ptr());
+    } else if let Err(trap) = interpret(
+        &program,
+        &Config {
+            max_steps,
+            ..Config::default()
+        },
+    ) {
+        let pc = match trap {
+            Trap::PointerUnderflow { pc } => pc,
+            Trap::PointerOverflow { pc, .. } => pc,
+            Trap::StepLimitExceeded { pc } => pc,
+        };
+        eprintln!(
+            "{:?} at pc={} ({})",
+            trap, pc, program.instructions[pc]
+        );
+        std::process::exit(1);
     }
 }
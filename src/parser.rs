@@ -1,6 +1,6 @@
 use std::fmt::{self, Display};
 use std::iter::Peekable;
-use std::str::Chars;
+use std::str::CharIndices;
 
 #[derive(PartialEq, Clone, Hash, Eq, Debug)]
 pub(crate) enum Opcode {
@@ -35,24 +35,37 @@ impl Display for Opcode {
     }
 }
 
+/// The position of a `[` waiting for its matching `]`: the pc it was
+/// assigned plus where it sits in the source, for diagnostics if it's
+/// never closed.
+struct OpenBracket {
+    pc: usize,
+    offset: usize,
+    line: usize,
+    column: usize,
+}
+
+#[derive(Debug)]
 pub(crate) struct Program {
     pub(crate) instructions: Vec<Opcode>,
 }
 
 impl Program {
-    pub(crate) fn from_source(source: String) -> Self {
+    pub(crate) fn from_source(source: String) -> Result<Self, ParseError> {
         let mut instructions = Vec::with_capacity(source.len());
 
-        let mut bracket_stack = vec![];
+        let mut bracket_stack: Vec<OpenBracket> = vec![];
+
+        let mut cursor = Cursor::new(&source);
 
-        let mut source_iter = source.chars().into_iter().peekable();
+        while cursor.peek().is_some() {
+            let (offset, ch, line, column) = cursor.next().unwrap();
 
-        while source_iter.peek().is_some() {
-            let insn = match source_iter.next().unwrap() {
-                '>' => Opcode::IncPtr(count_occ('>', &mut source_iter)),
-                '<' => Opcode::DecPtr(count_occ('<', &mut source_iter)),
-                '+' => Opcode::IncData(count_occ('+', &mut source_iter)),
-                '-' => Opcode::DecData(count_occ('-', &mut source_iter)),
+            let insn = match ch {
+                '>' => Opcode::IncPtr(count_occ('>', &mut cursor)),
+                '<' => Opcode::DecPtr(count_occ('<', &mut cursor)),
+                '+' => Opcode::IncData(count_occ('+', &mut cursor)),
+                '-' => Opcode::DecData(count_occ('-', &mut cursor)),
                 ',' => Opcode::ReadStdin,
                 '.' => Opcode::WriteStdout,
                 '[' => Opcode::JumpIfDataZero(instructions.len()),
@@ -61,25 +74,34 @@ impl Program {
             };
 
             if let Opcode::JumpIfDataZero(opening_pc) = insn {
-                bracket_stack.push(opening_pc);
+                bracket_stack.push(OpenBracket {
+                    pc: opening_pc,
+                    offset,
+                    line,
+                    column,
+                });
             }
 
             if let Opcode::JumpIfDataNotZero(closing_pc) = insn {
-                if bracket_stack.is_empty() {
-                    panic!("unmatched ']' at pc={}", closing_pc);
-                }
-
-                let opening_pc = bracket_stack.pop().unwrap();
+                let opening = bracket_stack.pop().ok_or_else(|| {
+                    ParseError::new(
+                        &source,
+                        offset,
+                        line,
+                        column,
+                        ParseErrorKind::UnmatchedCloseBracket,
+                    )
+                })?;
 
-                let loop_slice = &instructions[opening_pc + 1..];
+                let loop_slice = &instructions[opening.pc + 1..];
                 let optimized_loop = Self::optimize_loops(loop_slice);
 
                 if let Some(loop_insn) = optimized_loop {
-                    instructions.truncate(opening_pc);
+                    instructions.truncate(opening.pc);
                     instructions.push(loop_insn)
                 } else {
-                    instructions[opening_pc] = Opcode::JumpIfDataZero(closing_pc);
-                    instructions.push(Opcode::JumpIfDataNotZero(opening_pc));
+                    instructions[opening.pc] = Opcode::JumpIfDataZero(closing_pc);
+                    instructions.push(Opcode::JumpIfDataNotZero(opening.pc));
                 }
 
                 continue;
@@ -89,11 +111,17 @@ impl Program {
         }
 
         // ensure we closed all loops
-        if !bracket_stack.is_empty() {
-            panic!("unmatched '[' at pc={}", bracket_stack[0]);
+        if let Some(unclosed) = bracket_stack.first() {
+            return Err(ParseError::new(
+                &source,
+                unclosed.offset,
+                unclosed.line,
+                unclosed.column,
+                ParseErrorKind::UnmatchedOpenBracket,
+            ));
         }
 
-        Self { instructions }
+        Ok(Self { instructions })
     }
 
     fn optimize_loops(insn: &[Opcode]) -> Option<Opcode> {
@@ -138,12 +166,12 @@ impl Program {
     }
 }
 
-fn count_occ(val: char, iterator: &mut Peekable<Chars>) -> u8 {
+fn count_occ(val: char, cursor: &mut Cursor) -> u8 {
     let mut count = 1;
-    while let Some(c) = iterator.peek() {
-        if *c == val {
+    while let Some(c) = cursor.peek() {
+        if c == val {
             // consume
-            iterator.next();
+            cursor.next();
             count += 1;
         } else {
             break;
@@ -152,27 +180,131 @@ fn count_occ(val: char, iterator: &mut Peekable<Chars>) -> u8 {
     count
 }
 
+/// Walks `source` char-by-char, tracking the byte offset and 1-based
+/// line/column of the next character to be read. Used so parse errors can
+/// point at the exact source position of an offending bracket.
+struct Cursor<'a> {
+    chars: Peekable<CharIndices<'a>>,
+    line: usize,
+    column: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn new(source: &'a str) -> Self {
+        Self {
+            chars: source.char_indices().peekable(),
+            line: 1,
+            column: 1,
+        }
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|(_, c)| *c)
+    }
+
+    /// Returns the offset, char, and (line, column) of the char just
+    /// consumed - i.e. its own position, not the position after it.
+    fn next(&mut self) -> Option<(usize, char, usize, usize)> {
+        let (offset, ch) = self.chars.next()?;
+        let (line, column) = (self.line, self.column);
+
+        if ch == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+
+        Some((offset, ch, line, column))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParseErrorKind {
+    UnmatchedOpenBracket,
+    UnmatchedCloseBracket,
+}
+
+/// A `[`/`]` mismatch in the source, located by byte offset plus the line
+/// and column it was computed from, with a rendered source snippet so the
+/// caller can show exactly where parsing failed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ParseError {
+    pub(crate) offset: usize,
+    pub(crate) line: usize,
+    pub(crate) column: usize,
+    kind: ParseErrorKind,
+    snippet: String,
+}
+
+impl ParseError {
+    fn new(source: &str, offset: usize, line: usize, column: usize, kind: ParseErrorKind) -> Self {
+        let source_line = source.lines().nth(line - 1).unwrap_or("");
+        let caret = format!("{}^", " ".repeat(column.saturating_sub(1)));
+
+        Self {
+            offset,
+            line,
+            column,
+            kind,
+            snippet: format!("{}\n{}", source_line, caret),
+        }
+    }
+}
+
+impl Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bracket = match self.kind {
+            ParseErrorKind::UnmatchedOpenBracket => '[',
+            ParseErrorKind::UnmatchedCloseBracket => ']',
+        };
+
+        writeln!(
+            f,
+            "unmatched '{}' at {}:{} (byte offset {})",
+            bracket, self.line, self.column, self.offset
+        )?;
+        write!(f, "{}", self.snippet)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     #[test]
     fn loop_optimization() {
-        let program = Program::from_source(String::from("[-]"));
+        let program = Program::from_source(String::from("[-]")).unwrap();
         assert_eq!(program.instructions.len(), 1);
         assert_eq!(program.instructions[0], Opcode::LoopSetToZero);
 
-        let program = Program::from_source(String::from("[>>]"));
+        let program = Program::from_source(String::from("[>>]")).unwrap();
         assert_eq!(program.instructions.len(), 1);
         assert_eq!(program.instructions[0], Opcode::LoopMovePtr(2, true));
 
-        let program = Program::from_source(String::from("[->>>+<<<]"));
+        let program = Program::from_source(String::from("[->>>+<<<]")).unwrap();
         assert_eq!(program.instructions.len(), 1);
         assert_eq!(program.instructions[0], Opcode::LoopMoveData(3, true));
 
-        let program = Program::from_source(String::from(">>>[-<<<<<<+>>>>>>]"));
+        let program = Program::from_source(String::from(">>>[-<<<<<<+>>>>>>]")).unwrap();
         assert_eq!(program.instructions.len(), 2);
         assert_eq!(program.instructions[0], Opcode::IncPtr(3));
         assert_eq!(program.instructions[1], Opcode::LoopMoveData(6, false));
     }
+
+    #[test]
+    fn unmatched_open_bracket_reports_its_position() {
+        let err = Program::from_source(String::from("+[+")).unwrap_err();
+        assert_eq!(err.offset, 1);
+        assert_eq!(err.line, 1);
+        assert_eq!(err.column, 2);
+    }
+
+    #[test]
+    fn unmatched_close_bracket_reports_its_position() {
+        let err = Program::from_source(String::from("+]")).unwrap_err();
+        assert_eq!(err.offset, 1);
+        assert_eq!(err.line, 1);
+        assert_eq!(err.column, 2);
+    }
 }